@@ -2,10 +2,12 @@ use cargo_metadata::camino::Utf8PathBuf;
 use cargo_metadata::Message;
 use colored::Colorize;
 use std::path::{Path, PathBuf};
+use std::process::Command;
 
 use crate::bolt::bolt_rustflags;
 use crate::bolt::env::{find_bolt_env, BoltEnv};
-use crate::build::{cargo_command_with_flags, handle_metadata_message};
+use crate::bolt::selection::TargetSelectionArgs;
+use crate::build::{cargo_command_with_flags, handle_metadata_message, BuildStd, InstrumentationScope};
 use crate::cli::cli_format_path;
 use crate::pgo::CargoCommand;
 use crate::workspace::{get_bolt_directory, get_cargo_workspace};
@@ -14,6 +16,28 @@ use crate::{clear_directory, run_command};
 #[derive(clap::Parser, Debug)]
 #[clap(trailing_var_arg(true))]
 pub struct BoltInstrumentArgs {
+    /// Only instrument units belonging to a workspace member, leaving dependencies,
+    /// proc-macros and build scripts untouched. This produces more precise BOLT profiles at
+    /// the cost of not profiling any instrumentation-sensitive code in your dependencies.
+    #[clap(long)]
+    instrument_only_local: bool,
+
+    /// Also rebuild the standard library from source under the instrumentation flags, so that
+    /// `std` code (allocation, formatting, iterators, ...) is covered by the collected profiles
+    /// too. Requires a nightly toolchain and an explicit `--target`.
+    #[clap(long)]
+    build_std: bool,
+
+    /// Collect BOLT profiles by sampling with `perf` instead of instrumenting the binary.
+    /// Instrumentation roughly doubles the binary's runtime, which makes profiling
+    /// production-like workloads impractical; sampling has much lower overhead, at the cost of
+    /// requiring a Linux host with `perf` and LBR (Last Branch Record) support.
+    #[clap(long)]
+    sampling: bool,
+
+    #[clap(flatten)]
+    selection: TargetSelectionArgs,
+
     /// Additional arguments that will be passed to `cargo build`.
     cargo_args: Vec<String>,
 }
@@ -24,6 +48,7 @@ pub fn bolt_instrument(args: BoltInstrumentArgs) -> anyhow::Result<()> {
     let bolt_dir = get_bolt_directory(&workspace)?;
 
     let bolt_env = find_bolt_env()?;
+    let selection = args.selection.validate(&workspace)?;
 
     if bolt_dir.exists() {
         log::info!("Profile directory already exists, it will be cleared");
@@ -32,23 +57,56 @@ pub fn bolt_instrument(args: BoltInstrumentArgs) -> anyhow::Result<()> {
 
     log::info!("BOLT profiles will be stored into {}", bolt_dir.display());
 
-    let output = cargo_command_with_flags(CargoCommand::Build, bolt_rustflags(), args.cargo_args)?;
+    if args.sampling {
+        check_perf_lbr_support();
+    }
+
+    let scope = if args.instrument_only_local {
+        InstrumentationScope::WorkspaceOnly
+    } else {
+        InstrumentationScope::Everything
+    };
+    let build_std = if args.build_std {
+        BuildStd::FromSource
+    } else {
+        BuildStd::Precompiled
+    };
+    let output = cargo_command_with_flags(
+        CargoCommand::Build,
+        bolt_rustflags(),
+        args.cargo_args,
+        scope,
+        build_std,
+    )?;
 
     for message in Message::parse_stream(output.stdout.as_slice()) {
         let message = message?;
         match message {
             Message::CompilerArtifact(artifact) => {
                 if let Some(executable) = artifact.executable {
-                    log::info!(
-                        "Binary {} built successfully. It will be now instrumented with BOLT.",
-                        artifact.target.name.blue(),
-                    );
-                    let instrumented_path = instrument_binary(&bolt_env, &executable, &bolt_dir)?;
-                    log::info!(
-                        "Binary {} instrumented successfully. Now run {} on your workload",
-                        artifact.target.name.blue(),
-                        cli_format_path(&instrumented_path.display())
-                    );
+                    if !selection.matches(&artifact) {
+                        continue;
+                    }
+                    if args.sampling {
+                        log::info!(
+                            "Binary {} built successfully. Record a profile for it by running:\n\
+                             perf record -e cycles:u -j any,u -o perf.data -- {} <your workload>",
+                            artifact.target.name.blue(),
+                            cli_format_path(&executable.as_std_path().display())
+                        );
+                    } else {
+                        log::info!(
+                            "Binary {} built successfully. It will be now instrumented with BOLT.",
+                            artifact.target.name.blue(),
+                        );
+                        let instrumented_path =
+                            instrument_binary(&bolt_env, &executable, &bolt_dir)?;
+                        log::info!(
+                            "Binary {} instrumented successfully. Now run {} on your workload",
+                            artifact.target.name.blue(),
+                            cli_format_path(&instrumented_path.display())
+                        );
+                    }
                 }
             }
             Message::BuildFinished(res) => {
@@ -68,6 +126,30 @@ pub fn bolt_instrument(args: BoltInstrumentArgs) -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Checks whether the host's `perf` supports LBR (Last Branch Record) sampling, which
+/// `--sampling` mode and the `perf2bolt` conversion step both rely on to reconstruct branch
+/// profiles without instrumentation.
+fn check_perf_lbr_support() {
+    match Command::new("perf")
+        .args([
+            "record", "-e", "cycles:u", "-j", "any,u", "--dry-run", "-o", "/dev/null", "--",
+            "true",
+        ])
+        .output()
+    {
+        Ok(output) if !output.status.success() => {
+            log::warn!(
+                "Your `perf` does not seem to support LBR (Last Branch Record) sampling, which `cargo pgo bolt instrument --sampling` relies on:\n{}",
+                String::from_utf8_lossy(&output.stderr)
+            );
+        }
+        Err(error) => {
+            log::warn!("Could not check `perf` for LBR support: {}", error);
+        }
+        _ => {}
+    }
+}
+
 /// Instruments a binary using BOLT.
 /// If it succeeds, returns the path to the instrumented binary.
 fn instrument_binary(
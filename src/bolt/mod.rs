@@ -1,6 +1,7 @@
 pub(crate) mod env;
 pub mod instrument;
 pub mod optimize;
+pub(crate) mod selection;
 
 pub fn llvm_bolt_install_hint() -> &'static str {
     "Build LLVM with BOLT and add its `bin` directory to PATH."
@@ -0,0 +1,249 @@
+use cargo::core::Workspace;
+use cargo_metadata::{Artifact, MetadataCommand, PackageId};
+use std::collections::{HashMap, HashSet};
+
+/// Package/target selection flags, mirroring `cargo fmt`'s `-p`/`--package` and target
+/// selection options. Used to narrow down which binaries get instrumented or optimized in a
+/// large workspace, instead of doing it for every executable.
+///
+/// For BOLT, only the selected binaries are instrumented or optimized. For PGO, the build still
+/// compiles every target with the instrumentation or optimization flags; the selection only
+/// decides which of the produced binaries are reported.
+#[derive(clap::Parser, Debug, Default)]
+pub struct TargetSelectionArgs {
+    /// Only instrument/optimize targets belonging to this package. Can be passed multiple times.
+    #[clap(long = "package", short = 'p')]
+    package: Vec<String>,
+
+    /// Only instrument/optimize this binary target. Can be passed multiple times.
+    #[clap(long = "bin")]
+    bin: Vec<String>,
+
+    /// Only instrument/optimize this example target. Can be passed multiple times.
+    #[clap(long = "example")]
+    example: Vec<String>,
+
+    /// Only instrument/optimize this benchmark target. Can be passed multiple times.
+    #[clap(long = "bench")]
+    bench: Vec<String>,
+}
+
+impl TargetSelectionArgs {
+    /// Returns the sole `--bin` selector, if exactly one was given and no `--package`,
+    /// `--example` or `--bench` selector was given. Used to require a selection be pinned down
+    /// to a single binary, as opposed to [`validate`](Self::validate), which only narrows it.
+    pub(crate) fn single_bin(&self) -> Option<&str> {
+        if self.bin.len() == 1
+            && self.package.is_empty()
+            && self.example.is_empty()
+            && self.bench.is_empty()
+        {
+            Some(self.bin[0].as_str())
+        } else {
+            None
+        }
+    }
+
+    /// Validates the selectors against the workspace metadata, returning a helpful error that
+    /// lists the available packages/targets when a selector does not match anything.
+    pub fn validate(&self, workspace: &Workspace) -> anyhow::Result<TargetSelection> {
+        let available_packages: Vec<String> = workspace
+            .members()
+            .map(|package| package.name().to_string())
+            .collect();
+        validate_selector("--package", &self.package, &available_packages)?;
+
+        let mut available_bins = Vec::new();
+        let mut available_examples = Vec::new();
+        let mut available_benches = Vec::new();
+        for package in workspace.members() {
+            for target in package.targets() {
+                if target.is_bin() {
+                    available_bins.push(target.name().to_string());
+                } else if target.is_example() {
+                    available_examples.push(target.name().to_string());
+                } else if target.is_bench() {
+                    available_benches.push(target.name().to_string());
+                }
+            }
+        }
+        validate_selector("--bin", &self.bin, &available_bins)?;
+        validate_selector("--example", &self.example, &available_examples)?;
+        validate_selector("--bench", &self.bench, &available_benches)?;
+
+        // `cargo_metadata::Artifact::package_id` is only meaningful together with the
+        // `cargo_metadata::Metadata` that produced it, so we resolve package names up front
+        // instead of guessing at the `PackageId`'s string representation later.
+        let metadata = MetadataCommand::new()
+            .current_dir(workspace.root())
+            .exec()
+            .map_err(|error| anyhow::anyhow!("Failed to query `cargo metadata`: {}", error))?;
+        let package_names: HashMap<PackageId, String> = metadata
+            .packages
+            .into_iter()
+            .map(|package| (package.id, package.name.to_string()))
+            .collect();
+
+        Ok(TargetSelection {
+            packages: self.package.iter().cloned().collect(),
+            bins: self.bin.iter().cloned().collect(),
+            examples: self.example.iter().cloned().collect(),
+            benches: self.bench.iter().cloned().collect(),
+            package_names,
+        })
+    }
+}
+
+fn validate_selector(flag: &str, selected: &[String], available: &[String]) -> anyhow::Result<()> {
+    for name in selected {
+        if !available.contains(name) {
+            return Err(anyhow::anyhow!(
+                "`{} {}` does not match any target in this workspace. Available targets: {}",
+                flag,
+                name,
+                if available.is_empty() {
+                    "<none>".to_string()
+                } else {
+                    available.join(", ")
+                }
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// A validated package/target selection, used to filter which [`Artifact`]s should be
+/// instrumented or optimized. An empty selection matches everything, preserving the previous
+/// behavior of instrumenting every executable produced by the build.
+#[derive(Debug, Default)]
+pub struct TargetSelection {
+    packages: HashSet<String>,
+    bins: HashSet<String>,
+    examples: HashSet<String>,
+    benches: HashSet<String>,
+    /// Maps a `cargo_metadata::PackageId` to the package name it belongs to, resolved once
+    /// from `cargo metadata` in [`TargetSelectionArgs::validate`]. `Artifact::package_id`'s
+    /// `repr` is an opaque, version-dependent `PackageIdSpec` string (its format has changed
+    /// across Cargo releases), so it must never be parsed by hand.
+    package_names: HashMap<PackageId, String>,
+}
+
+impl TargetSelection {
+    fn is_empty(&self) -> bool {
+        self.packages.is_empty()
+            && self.bins.is_empty()
+            && self.examples.is_empty()
+            && self.benches.is_empty()
+    }
+
+    pub fn matches(&self, artifact: &Artifact) -> bool {
+        // `package_names` is built from the same workspace this artifact was built from, so the
+        // id should always be present; if it somehow isn't, fall back to a name that can never
+        // equal a real `--package` selector, rather than comparing against the opaque `repr`
+        // (which is exactly the bug this lookup replaces).
+        let package = self
+            .package_names
+            .get(&artifact.package_id)
+            .map(|name| name.as_str())
+            .unwrap_or("");
+        let kinds: Vec<&str> = artifact
+            .target
+            .kind
+            .iter()
+            .map(|kind| kind.as_str())
+            .collect();
+        self.matches_parts(package, artifact.target.name.as_str(), &kinds)
+    }
+
+    fn matches_parts(&self, package: &str, target_name: &str, kinds: &[&str]) -> bool {
+        if self.is_empty() {
+            return true;
+        }
+        if !self.packages.is_empty() && !self.packages.contains(package) {
+            return false;
+        }
+        if self.bins.is_empty() && self.examples.is_empty() && self.benches.is_empty() {
+            // Only `--package` selectors were given; any target of a matching package counts.
+            return true;
+        }
+        (self.bins.contains(target_name) && kinds.contains(&"bin"))
+            || (self.examples.contains(target_name) && kinds.contains(&"example"))
+            || (self.benches.contains(target_name) && kinds.contains(&"bench"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn selection(
+        packages: &[&str],
+        bins: &[&str],
+        examples: &[&str],
+        benches: &[&str],
+    ) -> TargetSelection {
+        TargetSelection {
+            packages: packages.iter().map(|s| s.to_string()).collect(),
+            bins: bins.iter().map(|s| s.to_string()).collect(),
+            examples: examples.iter().map(|s| s.to_string()).collect(),
+            benches: benches.iter().map(|s| s.to_string()).collect(),
+            package_names: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_empty_selection_matches_everything() {
+        let selection = selection(&[], &[], &[], &[]);
+        assert!(selection.matches_parts("foo", "foo", &["bin"]));
+        assert!(selection.matches_parts("bar", "baz", &["example"]));
+    }
+
+    #[test]
+    fn test_package_only_selection_matches_any_target_of_that_package() {
+        let selection = selection(&["foo"], &[], &[], &[]);
+        assert!(selection.matches_parts("foo", "whatever", &["bin"]));
+        assert!(selection.matches_parts("foo", "whatever", &["example"]));
+        assert!(!selection.matches_parts("bar", "whatever", &["bin"]));
+    }
+
+    #[test]
+    fn test_bin_selection_requires_matching_kind() {
+        let selection = selection(&[], &["mybin"], &[], &[]);
+        assert!(selection.matches_parts("foo", "mybin", &["bin"]));
+        assert!(!selection.matches_parts("foo", "mybin", &["example"]));
+        assert!(!selection.matches_parts("foo", "other", &["bin"]));
+    }
+
+    #[test]
+    fn test_validate_selector_reports_available_targets_on_mismatch() {
+        let error = validate_selector(
+            "--bin",
+            &["missing".to_string()],
+            &["present".to_string()],
+        )
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "`--bin missing` does not match any target in this workspace. Available targets: present"
+        );
+    }
+
+    #[test]
+    fn test_validate_selector_reports_none_when_workspace_has_no_targets_of_that_kind() {
+        let error = validate_selector("--bench", &["missing".to_string()], &[]).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "`--bench missing` does not match any target in this workspace. Available targets: <none>"
+        );
+    }
+
+    #[test]
+    fn test_validate_selector_passes_when_all_selectors_are_available() {
+        assert!(validate_selector(
+            "--package",
+            &["foo".to_string()],
+            &["foo".to_string(), "bar".to_string()]
+        )
+        .is_ok());
+    }
+}
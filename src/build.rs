@@ -1,91 +1,281 @@
 use crate::get_default_target;
 use crate::pgo::CargoCommand;
+use crate::workspace::get_cargo_workspace;
+use cargo::core::compiler::{CompileKind, CompileMode, CompileTarget, Executor};
+use cargo::core::resolver::CliFeatures;
+use cargo::core::{PackageId, Target};
+use cargo::ops::{self, CompileOptions, Packages};
+use cargo::util::ProcessBuilder;
+use cargo::CargoResult;
 use cargo_metadata::{Artifact, Message};
 use colored::Colorize;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fmt::Write as WriteFmt;
 use std::io::Write;
-use std::process::{Command, Output};
+use std::process::Command;
+use std::sync::{Arc, Mutex};
 
 #[derive(Debug, Default)]
 struct CargoArgs {
-    filtered: Vec<String>,
+    /// `--target <triple>`, if the user passed one explicitly. Must be honored as-is: it's the
+    /// one thing `--build-std` requires the caller to specify themselves.
+    target: Option<String>,
     contains_target: bool,
+    /// `-p`/`--package <name>`, collected so they can be turned into `CompileOptions::spec`.
+    packages: Vec<String>,
+    /// `--features <name>`, collected so they can be turned into `CompileOptions::cli_features`.
+    features: Vec<String>,
+    all_features: bool,
+    no_default_features: bool,
+    /// Anything we don't know how to translate into a `CompileOptions` field. Since we drive the
+    /// build in-process (no `cargo` subprocess to forward argv to), an arg landing here has no
+    /// effect on the build at all, so we report it back to the user instead of silently dropping
+    /// it.
+    unsupported: Vec<String>,
 }
 
-/// Run `cargo` command in release mode with the provided RUSTFLAGS and Cargo arguments.
+/// Which compilation units [`cargo_command_with_flags`] should attach instrumentation flags to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InstrumentationScope {
+    /// Instrument every unit built for the target platform (matches the previous,
+    /// global-`RUSTFLAGS` behavior).
+    Everything,
+    /// Only instrument units that belong to a workspace member, leaving dependencies,
+    /// proc-macros and build scripts untouched.
+    WorkspaceOnly,
+}
+
+/// Result of an in-process `cargo build`. We cannot produce a real [`std::process::Output`]
+/// (and its opaque, platform-specific `ExitStatus`) without actually spawning a process, so we
+/// carry just the pieces the rest of `cargo-pgo` needs: whether the build succeeded and the
+/// `--message-format=json` stream that cargo would otherwise have printed to its own stdout.
+#[derive(Debug, Default)]
+pub struct CargoBuildResult {
+    pub success: bool,
+    pub stdout: Vec<u8>,
+}
+
+/// Which parts of the standard library, if any, should be rebuilt under the same instrumentation
+/// flags as the profiled program. Rebuilding std lets `Vec`, formatting, allocation and iterator
+/// code show up in the collected profile, instead of only user code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuildStd {
+    /// Use the precompiled standard library shipped with the toolchain.
+    Precompiled,
+    /// Rebuild `std`/`core`/`alloc`/`panic_abort` from source with `-Z build-std`, in the spirit
+    /// of xargo's sysroot rebuilding. Requires a nightly toolchain and an explicit `--target`.
+    FromSource,
+}
+
+/// Run a `cargo` command in release mode, injecting `flags` only into the rustc invocations
+/// selected by `scope`.
+///
+/// Earlier versions of `cargo-pgo` did this by exporting a single global `RUSTFLAGS`, which also
+/// instruments proc-macros, build scripts and every dependency, inflating the collected profile
+/// with noise that has nothing to do with the profiled program. Instead, following how RLS and
+/// `rustfmt`'s cargo integration drive builds, we run the build in-process through
+/// [`cargo::ops::compile_with_exec`] with a custom [`Executor`] that inspects each unit and only
+/// attaches the flags to the ones we actually care about.
 pub fn cargo_command_with_flags(
     command: CargoCommand,
     flags: &str,
     cargo_args: Vec<String>,
-) -> anyhow::Result<Output> {
-    let mut rustflags = std::env::var("RUSTFLAGS").unwrap_or_default();
-    write!(&mut rustflags, " {}", flags).unwrap();
-
-    let mut env = HashMap::default();
-    env.insert("RUSTFLAGS".to_string(), rustflags);
-
-    let output = cargo_command(command, cargo_args, env)?;
-    if !output.status.success() {
-        Err(anyhow::anyhow!(
-            "Cargo error ({})\n{}\n{}",
-            output.status,
-            String::from_utf8_lossy(&output.stderr).red(),
-            cargo_json_output_to_string(&output.stdout)
-                .unwrap_or_else(|error| format!("Could not parse Cargo stdout: {}", error))
-        ))
-    } else {
-        Ok(output)
+    scope: InstrumentationScope,
+    build_std: BuildStd,
+) -> anyhow::Result<CargoBuildResult> {
+    let parsed_args = parse_cargo_args(cargo_args);
+
+    if build_std == BuildStd::FromSource && !parsed_args.contains_target {
+        return Err(anyhow::anyhow!(
+            "The `--build-std` option requires an explicit `--target`, because Cargo only \
+             rebuilds the standard library when one is specified. Pass e.g. `--target {}`.",
+            get_default_target().unwrap_or_else(|_| "<your target triple>".to_string())
+        ));
+    }
+    if build_std == BuildStd::FromSource && !is_nightly_toolchain()? {
+        return Err(anyhow::anyhow!(
+            "The `--build-std` option requires a nightly toolchain (`rustup install nightly`), \
+             because `-Z build-std` is unstable."
+        ));
     }
-}
 
-fn cargo_json_output_to_string(output: &[u8]) -> anyhow::Result<String> {
-    let mut messages = Vec::new();
+    let config = cargo::Config::default()?;
+    let workspace = get_cargo_workspace(&config)?;
 
-    for message in Message::parse_stream(output) {
-        let message = message?;
-        write_metadata_message(&mut messages, message);
-    }
+    // `compile_with_exec` only supports driving a build, which is all `CargoCommand` currently
+    // covers; kept as a parameter so future non-build commands can still go through this path.
+    let CargoCommand::Build = command;
+    let mut compile_opts = CompileOptions::new(&config, CompileMode::Build)?;
+    compile_opts.build_config.requested_profile = "release".into();
+    compile_opts.build_config.message_format = ops::MessageFormat::Json {
+        short: false,
+        ansi: true,
+        render_diagnostics: false,
+    };
 
-    Ok(String::from_utf8(messages)?)
-}
+    if build_std == BuildStd::FromSource {
+        config.configure(
+            0,
+            false,
+            None,
+            false,
+            false,
+            false,
+            &None,
+            &["build-std=std,core,alloc,panic_abort".to_string()],
+            &[],
+        )?;
+    }
 
-/// Run `cargo` command in release mode with the provided env variables and Cargo arguments.
-fn cargo_command(
-    cargo_cmd: CargoCommand,
-    cargo_args: Vec<String>,
-    env: HashMap<String, String>,
-) -> anyhow::Result<Output> {
-    let parsed_args = parse_cargo_args(cargo_args);
+    apply_cargo_args(&mut compile_opts, &parsed_args)?;
 
-    let mut command = Command::new("cargo");
-    command.args(&[
-        cargo_cmd.to_str(),
-        "--release",
-        "--message-format",
-        "json-diagnostic-rendered-ansi",
-    ]);
-
-    // --target is passed to avoid instrumenting build scripts
-    // See https://doc.rust-lang.org/rustc/profile-guided-optimization.html#a-complete-cargo-workflow
-    if !parsed_args.contains_target {
+    // --target is required to avoid instrumenting build scripts, which are always compiled for
+    // the host. See https://doc.rust-lang.org/rustc/profile-guided-optimization.html#a-complete-cargo-workflow
+    //
+    // If the user passed an explicit `--target`, it must actually be applied here too — not just
+    // detected — otherwise the build silently falls back to the host/default compile kind,
+    // which defeats the above invariant (and `--build-std`, which requires the user to pass
+    // `--target` themselves, would end up building nothing under it).
+    if let Some(target) = &parsed_args.target {
+        compile_opts.build_config.requested_kinds =
+            vec![CompileKind::Target(CompileTarget::new(target)?)];
+    } else {
         let default_target = get_default_target().map_err(|error| {
             anyhow::anyhow!(
                 "Unable to find default target triple for your platform: {:?}",
                 error
             )
         })?;
-        command.args(&["--target", &default_target]);
+        compile_opts.build_config.requested_kinds =
+            vec![CompileKind::Target(CompileTarget::new(&default_target)?)];
+    }
+
+    let workspace_members: HashSet<PackageId> =
+        workspace.members().map(|package| package.package_id()).collect();
+
+    let stdout = Arc::new(Mutex::new(Vec::new()));
+    let executor: Arc<dyn Executor> = Arc::new(InstrumentingExecutor {
+        rustflags: flags.to_string(),
+        scope,
+        workspace_members,
+        stdout: stdout.clone(),
+    });
+
+    let result = ops::compile_with_exec(&workspace, &compile_opts, &executor);
+    let stdout = Arc::try_unwrap(stdout)
+        .map(|mutex| mutex.into_inner().unwrap())
+        .unwrap_or_default();
+
+    match result {
+        Ok(_) => Ok(CargoBuildResult {
+            success: true,
+            stdout,
+        }),
+        // Not every build failure shows up on the JSON message stream (resolver errors, bad
+        // package/feature selection, manifest errors, ...), so the underlying `cargo::Error` has
+        // to be included directly rather than relying on `stdout` alone.
+        Err(error) => Err(anyhow::anyhow!(
+            "Cargo error: {:#}\n{}",
+            error,
+            cargo_json_output_to_string(&stdout)
+                .unwrap_or_else(|error| format!("Could not parse Cargo stdout: {}", error))
+        )),
+    }
+}
+
+/// Attaches instrumentation `RUSTFLAGS` only to the rustc invocations selected by `scope`,
+/// leaving everything else (host units, build scripts, and — in [`InstrumentationScope::WorkspaceOnly`]
+/// mode — dependencies and proc-macros) untouched.
+struct InstrumentingExecutor {
+    rustflags: String,
+    scope: InstrumentationScope,
+    workspace_members: HashSet<PackageId>,
+    stdout: Arc<Mutex<Vec<u8>>>,
+}
+
+impl InstrumentingExecutor {
+    fn should_instrument(
+        &self,
+        cmd: &ProcessBuilder,
+        id: PackageId,
+        target: &Target,
+        mode: CompileMode,
+    ) -> bool {
+        if mode.is_run_custom_build() {
+            return false;
+        }
+        // Cargo only passes `--target` to rustc for units built for the target platform; host
+        // units (build scripts and whatever they or proc-macros depend on) are compiled without it.
+        if !cmd.get_args().into_iter().any(|arg| arg == "--target") {
+            return false;
+        }
+        // Proc-macros always run on the host at compile time, not in the profiled program, so
+        // instrumenting them only adds noise to the collected profile — exclude them regardless
+        // of `scope`.
+        if target.proc_macro() {
+            return false;
+        }
+        match self.scope {
+            InstrumentationScope::Everything => true,
+            InstrumentationScope::WorkspaceOnly => self.workspace_members.contains(&id),
+        }
     }
+}
+
+impl Executor for InstrumentingExecutor {
+    fn exec(
+        &self,
+        cmd: &ProcessBuilder,
+        id: PackageId,
+        target: &Target,
+        mode: CompileMode,
+        on_stdout_line: &mut dyn FnMut(&str) -> CargoResult<()>,
+        on_stderr_line: &mut dyn FnMut(&str) -> CargoResult<()>,
+    ) -> CargoResult<()> {
+        // `Executor::exec` only hands us a `&ProcessBuilder`, so the extra rustc arguments below
+        // have to be appended to an owned clone rather than the borrowed `cmd`. rustc itself does
+        // not read `RUSTFLAGS` (cargo does), so the flags are passed as arguments.
+        let mut cmd = cmd.clone();
+        if self.should_instrument(&cmd, id, target, mode) {
+            for flag in self.rustflags.split_whitespace() {
+                cmd.arg(flag);
+            }
+        }
 
-    for arg in parsed_args.filtered {
-        command.arg(arg);
+        let stdout = self.stdout.clone();
+        cmd.exec_with_streaming(
+            &mut |line| {
+                let mut stdout = stdout.lock().unwrap();
+                writeln!(stdout, "{}", line).unwrap();
+                on_stdout_line(line)
+            },
+            on_stderr_line,
+            false,
+        )
+        .map(drop)
     }
-    for (key, value) in env {
-        command.env(key, value);
+}
+
+/// Checks whether the active `rustc` is a nightly toolchain, which `-Z` flags like `build-std`
+/// require.
+fn is_nightly_toolchain() -> anyhow::Result<bool> {
+    let output = crate::run_command("rustc", &["-Vv"])?;
+    Ok(output
+        .lines()
+        .find(|line| line.starts_with("release: "))
+        .map(|line| line.contains("nightly"))
+        .unwrap_or(false))
+}
+
+fn cargo_json_output_to_string(output: &[u8]) -> anyhow::Result<String> {
+    let mut messages = Vec::new();
+
+    for message in Message::parse_stream(output) {
+        let message = message?;
+        write_metadata_message(&mut messages, message);
     }
-    log::debug!("Executing cargo command: {:?}", command);
-    Ok(command.output()?)
+
+    Ok(String::from_utf8(messages)?)
 }
 
 fn parse_cargo_args(cargo_args: Vec<String>) -> CargoArgs {
@@ -105,14 +295,45 @@ fn parse_cargo_args(cargo_args: Vec<String>) -> CargoArgs {
             }
             "--target" => {
                 args.contains_target = true;
-                args.filtered.push(arg);
+                args.target = iterator.next();
             }
-            _ => args.filtered.push(arg),
+            "-p" | "--package" => {
+                if let Some(value) = iterator.next() {
+                    args.packages.push(value);
+                }
+            }
+            "--features" => {
+                if let Some(value) = iterator.next() {
+                    args.features.push(value);
+                }
+            }
+            "--all-features" => args.all_features = true,
+            "--no-default-features" => args.no_default_features = true,
+            _ => args.unsupported.push(arg),
         }
     }
     args
 }
 
+/// Applies the cargo args collected by [`parse_cargo_args`] onto `compile_opts`, so that
+/// package selectors and feature flags actually affect the in-process build instead of being
+/// silently discarded (the explicit `--target` value is applied separately, since it also
+/// decides whether [`cargo_command_with_flags`] injects the default target).
+fn apply_cargo_args(compile_opts: &mut CompileOptions, args: &CargoArgs) -> anyhow::Result<()> {
+    if !args.unsupported.is_empty() {
+        return Err(anyhow::anyhow!(
+            "Unsupported cargo argument(s): {}. `cargo-pgo` drives the build in-process and only \
+             understands `--target`, `-p`/`--package`, `--features`, `--all-features` and \
+             `--no-default-features`.",
+            args.unsupported.join(", ")
+        ));
+    }
+    compile_opts.spec = Packages::from_flags(false, Vec::new(), args.packages.clone())?;
+    compile_opts.cli_features =
+        CliFeatures::from_command_line(&args.features, args.all_features, !args.no_default_features)?;
+    Ok(())
+}
+
 pub fn handle_metadata_message(message: Message) {
     write_metadata_message(std::io::stdout().lock(), message);
 }
@@ -166,7 +387,10 @@ mod tests {
             "--release".to_string(),
             "--bar".to_string(),
         ]);
-        assert_eq!(args.filtered, vec!["foo".to_string(), "--bar".to_string()]);
+        assert_eq!(
+            args.unsupported,
+            vec!["foo".to_string(), "--bar".to_string()]
+        );
     }
 
     #[test]
@@ -177,7 +401,10 @@ mod tests {
             "json".to_string(),
             "bar".to_string(),
         ]);
-        assert_eq!(args.filtered, vec!["foo".to_string(), "bar".to_string()]);
+        assert_eq!(
+            args.unsupported,
+            vec!["foo".to_string(), "bar".to_string()]
+        );
     }
 
     #[test]
@@ -187,10 +414,27 @@ mod tests {
             "x64".to_string(),
             "bar".to_string(),
         ]);
-        assert_eq!(
-            args.filtered,
-            vec!["--target".to_string(), "x64".to_string(), "bar".to_string()]
-        );
         assert!(args.contains_target);
+        assert_eq!(args.target, Some("x64".to_string()));
+        assert_eq!(args.unsupported, vec!["bar".to_string()]);
+    }
+
+    #[test]
+    fn test_parse_cargo_args_collects_packages_and_features() {
+        let args = parse_cargo_args(vec![
+            "-p".to_string(),
+            "foo".to_string(),
+            "--package".to_string(),
+            "bar".to_string(),
+            "--features".to_string(),
+            "a,b".to_string(),
+            "--all-features".to_string(),
+            "--no-default-features".to_string(),
+        ]);
+        assert_eq!(args.packages, vec!["foo".to_string(), "bar".to_string()]);
+        assert_eq!(args.features, vec!["a,b".to_string()]);
+        assert!(args.all_features);
+        assert!(args.no_default_features);
+        assert!(args.unsupported.is_empty());
     }
 }
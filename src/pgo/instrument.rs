@@ -0,0 +1,96 @@
+use cargo_metadata::Message;
+use colored::Colorize;
+
+use crate::bolt::selection::TargetSelectionArgs;
+use crate::build::{
+    cargo_command_with_flags, handle_metadata_message, BuildStd, InstrumentationScope,
+};
+use crate::cli::cli_format_path;
+use crate::clear_directory;
+use crate::pgo::{pgo_instrument_rustflags, CargoCommand};
+use crate::workspace::{get_cargo_workspace, get_pgo_directory};
+
+#[derive(clap::Parser, Debug)]
+#[clap(trailing_var_arg(true))]
+pub struct PgoInstrumentArgs {
+    /// Only instrument units belonging to a workspace member, leaving dependencies,
+    /// proc-macros and build scripts untouched.
+    #[clap(long)]
+    instrument_only_local: bool,
+
+    /// Also rebuild the standard library from source under the instrumentation flags, so that
+    /// `std` code (allocation, formatting, iterators, ...) is covered by the collected profile
+    /// too. Requires a nightly toolchain and an explicit `--target`.
+    #[clap(long)]
+    build_std: bool,
+
+    #[clap(flatten)]
+    selection: TargetSelectionArgs,
+
+    /// Additional arguments that will be passed to `cargo build`.
+    cargo_args: Vec<String>,
+}
+
+pub fn pgo_instrument(args: PgoInstrumentArgs) -> anyhow::Result<()> {
+    let config = cargo::Config::default()?;
+    let workspace = get_cargo_workspace(&config)?;
+    let pgo_dir = get_pgo_directory(&workspace)?;
+    let selection = args.selection.validate(&workspace)?;
+
+    if pgo_dir.exists() {
+        log::info!("Profile directory already exists, it will be cleared");
+        clear_directory(&pgo_dir)?;
+    }
+    log::info!("PGO profiles will be stored into {}", pgo_dir.display());
+
+    let scope = if args.instrument_only_local {
+        InstrumentationScope::WorkspaceOnly
+    } else {
+        InstrumentationScope::Everything
+    };
+    let build_std = if args.build_std {
+        BuildStd::FromSource
+    } else {
+        BuildStd::Precompiled
+    };
+
+    let output = cargo_command_with_flags(
+        CargoCommand::Build,
+        &pgo_instrument_rustflags(&pgo_dir),
+        args.cargo_args,
+        scope,
+        build_std,
+    )?;
+
+    for message in Message::parse_stream(output.stdout.as_slice()) {
+        let message = message?;
+        match message {
+            Message::CompilerArtifact(artifact) => {
+                if let Some(executable) = artifact.executable {
+                    if !selection.matches(&artifact) {
+                        continue;
+                    }
+                    log::info!(
+                        "Binary {} built successfully. Run {} on a representative workload to \
+                         generate a PGO profile, then run `cargo pgo optimize`.",
+                        artifact.target.name.blue(),
+                        cli_format_path(&executable.as_std_path().display())
+                    );
+                }
+            }
+            Message::BuildFinished(res) => {
+                if res.success {
+                    log::info!(
+                        "PGO instrumentation build finished {}",
+                        "successfully".green()
+                    );
+                } else {
+                    log::error!("PGO instrumentation build has {}", "failed".red());
+                }
+            }
+            _ => handle_metadata_message(message),
+        }
+    }
+
+    Ok(())
+}
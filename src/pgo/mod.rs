@@ -0,0 +1,26 @@
+pub mod instrument;
+pub mod optimize;
+
+use std::path::Path;
+
+/// Which `cargo` command should be driven in-process by
+/// [`crate::build::cargo_command_with_flags`]. Currently only `build` is needed; kept as an enum
+/// (also used by the `bolt` module) so a future command has somewhere to go.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CargoCommand {
+    Build,
+}
+
+/// `RUSTFLAGS` that make rustc instrument selected units to collect a PGO profile into
+/// `profile_dir`.
+pub(crate) fn pgo_instrument_rustflags(profile_dir: &Path) -> String {
+    format!("-Cprofile-generate={}", profile_dir.display())
+}
+
+/// `RUSTFLAGS` that make rustc consume a merged PGO profile when rebuilding the optimized binary.
+pub(crate) fn pgo_optimize_rustflags(merged_profile: &Path) -> String {
+    format!(
+        "-Cprofile-use={} -Cllvm-args=-pgo-warn-missing-function",
+        merged_profile.display()
+    )
+}
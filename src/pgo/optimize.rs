@@ -0,0 +1,144 @@
+use std::path::{Path, PathBuf};
+
+use cargo_metadata::Message;
+use colored::Colorize;
+
+use crate::bolt::selection::TargetSelectionArgs;
+use crate::build::{
+    cargo_command_with_flags, handle_metadata_message, BuildStd, InstrumentationScope,
+};
+use crate::cli::cli_format_path;
+use crate::pgo::{pgo_optimize_rustflags, CargoCommand};
+use crate::workspace::{get_cargo_workspace, get_pgo_directory};
+use crate::{get_default_target, resolve_binary, run_command};
+
+#[derive(clap::Parser, Debug)]
+#[clap(trailing_var_arg(true))]
+pub struct PgoOptimizeArgs {
+    /// Rebuild the standard library from source; see `cargo pgo instrument --build-std`. Must
+    /// match whatever was passed to `instrument`, since the recompiled std has to consume the
+    /// same profile it helped produce.
+    #[clap(long)]
+    build_std: bool,
+
+    #[clap(flatten)]
+    selection: TargetSelectionArgs,
+
+    /// Additional arguments that will be passed to `cargo build`.
+    cargo_args: Vec<String>,
+}
+
+pub fn pgo_optimize(args: PgoOptimizeArgs) -> anyhow::Result<()> {
+    let config = cargo::Config::default()?;
+    let workspace = get_cargo_workspace(&config)?;
+    let pgo_dir = get_pgo_directory(&workspace)?;
+    let selection = args.selection.validate(&workspace)?;
+
+    let profiles = find_profraw_files(&pgo_dir)?;
+    if profiles.is_empty() {
+        return Err(anyhow::anyhow!(
+            "No PGO profiles found in {}. Run `cargo pgo instrument` and exercise the \
+             resulting binary first.",
+            pgo_dir.display()
+        ));
+    }
+
+    let merged_profile = pgo_dir.join("merged.profdata");
+    merge_profiles(&profiles, &merged_profile)?;
+    log::info!(
+        "Merged {} PGO profile(s) into {}",
+        profiles.len(),
+        merged_profile.display()
+    );
+
+    let build_std = if args.build_std {
+        BuildStd::FromSource
+    } else {
+        BuildStd::Precompiled
+    };
+    let output = cargo_command_with_flags(
+        CargoCommand::Build,
+        &pgo_optimize_rustflags(&merged_profile),
+        args.cargo_args,
+        InstrumentationScope::Everything,
+        build_std,
+    )?;
+
+    for message in Message::parse_stream(output.stdout.as_slice()) {
+        let message = message?;
+        match message {
+            Message::CompilerArtifact(artifact) => {
+                if let Some(executable) = artifact.executable {
+                    if !selection.matches(&artifact) {
+                        continue;
+                    }
+                    log::info!(
+                        "Binary {} optimized successfully. You can find it at {}",
+                        artifact.target.name.blue(),
+                        cli_format_path(&executable.as_std_path().display())
+                    );
+                }
+            }
+            Message::BuildFinished(res) => {
+                if res.success {
+                    log::info!("PGO optimization build finished {}", "successfully".green());
+                } else {
+                    log::error!("PGO optimization build has {}", "failed".red());
+                }
+            }
+            _ => handle_metadata_message(message),
+        }
+    }
+
+    Ok(())
+}
+
+/// Finds the `.profraw` files written by instrumented binaries built with `cargo pgo instrument`.
+fn find_profraw_files(pgo_dir: &Path) -> anyhow::Result<Vec<String>> {
+    let mut profiles = Vec::new();
+    if !pgo_dir.is_dir() {
+        return Ok(profiles);
+    }
+    for entry in std::fs::read_dir(pgo_dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|ext| ext.to_str()) == Some("profraw") {
+            if let Some(path) = path.to_str() {
+                profiles.push(path.to_string());
+            }
+        }
+    }
+    Ok(profiles)
+}
+
+/// Merges `.profraw` files into a single `.profdata` file with `llvm-profdata merge`.
+fn merge_profiles(profiles: &[String], merged_profile: &Path) -> anyhow::Result<()> {
+    let profdata = find_llvm_profdata()?;
+    let merged_profile_str = merged_profile
+        .to_str()
+        .ok_or_else(|| anyhow::anyhow!("PGO profile directory path is not valid UTF-8"))?;
+
+    let mut merge_args: Vec<&str> = vec!["merge", "-o", merged_profile_str];
+    merge_args.extend(profiles.iter().map(|profile| profile.as_str()));
+    run_command(&profdata, &merge_args)?;
+    Ok(())
+}
+
+/// Locates `llvm-profdata`, which ships with the `llvm-tools` rustup component installed next to
+/// `rustc` rather than elsewhere on `PATH`.
+fn find_llvm_profdata() -> anyhow::Result<PathBuf> {
+    let sysroot = run_command("rustc", &["--print", "sysroot"])?;
+    let host = get_default_target()?;
+    let candidate = Path::new(sysroot.trim())
+        .join("lib/rustlib")
+        .join(&host)
+        .join("bin")
+        .join("llvm-profdata");
+    if candidate.is_file() {
+        return Ok(candidate);
+    }
+    resolve_binary(Path::new("llvm-profdata")).map_err(|_| {
+        anyhow::anyhow!(
+            "Could not find `llvm-profdata`. Install it with `rustup component add llvm-tools`."
+        )
+    })
+}